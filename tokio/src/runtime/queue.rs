@@ -1,12 +1,13 @@
 //! Run-queue structures to support a work-stealing scheduler
 
 use crate::loom::cell::UnsafeCell;
-use crate::loom::sync::atomic::{AtomicU16, AtomicU32};
+use crate::loom::sync::atomic::{AtomicPtr, AtomicU16, AtomicU32, AtomicU64};
 use crate::loom::sync::Arc;
 use crate::runtime::stats::{WorkerStats, WorkerStatsBatcher};
 use crate::runtime::task::{self, Inject};
 
 use std::mem::MaybeUninit;
+use std::ops::{Deref, DerefMut};
 use std::ptr;
 use std::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed, Release};
 
@@ -30,18 +31,92 @@ pub(super) struct Inner<T: 'static> {
     /// When both `u16` values are the same, there is no active stealer.
     ///
     /// Tracking an in-progress stealer prevents a wrapping scenario.
-    head: AtomicU32,
+    ///
+    /// Cache-padded because it's hammered by stealers via CAS on every
+    /// steal, while `tail` right below it is written by the producer on
+    /// every `push_back`; without padding the two sit on the same cache
+    /// line and each side's writes needlessly invalidate the other's.
+    head: CachePadded<AtomicU32>,
 
     /// Only updated by producer thread but read by many threads.
-    tail: AtomicU16,
-
-    /// Elements
-    buffer: Box<[UnsafeCell<MaybeUninit<task::Notified<T>>>; LOCAL_QUEUE_CAPACITY]>,
+    tail: CachePadded<AtomicU16>,
+
+    /// Backing storage for the queue. `Local::grow` swaps this out for a
+    /// larger allocation in place rather than shuffling elements within a
+    /// fixed-size buffer, since a stealer may be concurrently reading out of
+    /// whichever `Buf` it observes. See `Buf` and `Local::grow`.
+    buf: AtomicPtr<Buf<T>>,
+
+    /// Hazard-pointer-style slot for the single stealer (if any) that may be
+    /// concurrently reading out of `buf`. While `steal_into2` or
+    /// `steal_into_inject` is reading, it publishes the generation it pinned,
+    /// plus one, here (`0` means unpinned) so `reclaim_retired` won't free that `Buf`
+    /// out from under it. A single slot suffices because `claim_batch`
+    /// rejects any steal attempt while `head`'s `steal` and `real` halves
+    /// differ, so at most one steal window — and thus at most one pinned
+    /// reader — can be open on this `Inner` at a time. See
+    /// `Inner::pin_reader`.
+    reader: AtomicU64,
+
+    /// `Buf`s previously replaced by `Local::grow`, kept alive until
+    /// `reclaim_retired` determines no stealer can still be reading from
+    /// them. Only ever touched by the producer thread.
+    retired: UnsafeCell<Vec<Box<Buf<T>>>>,
 }
 
 unsafe impl<T> Send for Inner<T> {}
 unsafe impl<T> Sync for Inner<T> {}
 
+/// Pads `T` out to a cache line so that adjacent fields on either side don't
+/// share it, avoiding false sharing between threads hammering one field and
+/// threads hammering the other.
+///
+/// Under loom, padding is irrelevant to the model checker and would only
+/// slow it down, so `CachePadded` is a transparent no-op there.
+#[cfg_attr(
+    not(loom),
+    cfg_attr(
+        any(
+            target_arch = "x86_64",
+            target_arch = "aarch64",
+            target_arch = "powerpc64"
+        ),
+        repr(align(128))
+    )
+)]
+#[cfg_attr(
+    not(loom),
+    cfg_attr(
+        not(any(
+            target_arch = "x86_64",
+            target_arch = "aarch64",
+            target_arch = "powerpc64"
+        )),
+        repr(align(64))
+    )
+)]
+struct CachePadded<T>(T);
+
+impl<T> CachePadded<T> {
+    fn new(inner: T) -> CachePadded<T> {
+        CachePadded(inner)
+    }
+}
+
+impl<T> Deref for CachePadded<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for CachePadded<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
 #[cfg(not(loom))]
 const LOCAL_QUEUE_CAPACITY: usize = 256;
 
@@ -51,31 +126,61 @@ const LOCAL_QUEUE_CAPACITY: usize = 256;
 #[cfg(loom)]
 const LOCAL_QUEUE_CAPACITY: usize = 4;
 
-const MASK: usize = LOCAL_QUEUE_CAPACITY - 1;
+/// The largest the local queue's backing buffer is allowed to grow to. Pos
+/// values are packed into `u16`s, so this must stay well clear of wrapping;
+/// once a queue has grown this large, further overflow goes to the inject
+/// queue exactly as it always has.
+const MAX_LOCAL_QUEUE_CAPACITY: usize = 1 << 15;
+
+/// A single generation of the local queue's backing storage.
+///
+/// `Local::grow` allocates a new, larger `Buf` and swaps it into
+/// `Inner::buf` rather than resizing this one in place, since stealers may be
+/// concurrently reading out of it. The old `Buf` is retired (kept around,
+/// unreachable from `Inner::buf`) until `Inner::reclaim_retired` proves no
+/// stealer can still observe it.
+struct Buf<T: 'static> {
+    /// Monotonically increasing identifier for this allocation, used by
+    /// stealers to recognize which generation they pinned.
+    generation: u64,
+
+    /// `capacity - 1`. The capacity is always a power of two.
+    mask: usize,
+
+    cells: Box<[UnsafeCell<MaybeUninit<task::Notified<T>>>]>,
+}
+
+impl<T: 'static> Buf<T> {
+    fn new(cap: usize, generation: u64) -> Box<Buf<T>> {
+        debug_assert!(cap.is_power_of_two());
 
-// Constructing the fixed size array directly is very awkward. The only way to
-// do it is to repeat `UnsafeCell::new(MaybeUninit::uninit())` 256 times, as
-// the contents are not Copy. The trick with defining a const doesn't work for
-// generic types.
-fn make_fixed_size<T>(buffer: Box<[T]>) -> Box<[T; LOCAL_QUEUE_CAPACITY]> {
-    assert_eq!(buffer.len(), LOCAL_QUEUE_CAPACITY);
+        let mut cells = Vec::with_capacity(cap);
 
-    // safety: We check that the length is correct.
-    unsafe { Box::from_raw(Box::into_raw(buffer).cast()) }
-}
+        for _ in 0..cap {
+            cells.push(UnsafeCell::new(MaybeUninit::uninit()));
+        }
 
-/// Create a new local run-queue
-pub(super) fn local<T: 'static>() -> (Steal<T>, Local<T>) {
-    let mut buffer = Vec::with_capacity(LOCAL_QUEUE_CAPACITY);
+        Box::new(Buf {
+            generation,
+            mask: cap - 1,
+            cells: cells.into_boxed_slice(),
+        })
+    }
 
-    for _ in 0..LOCAL_QUEUE_CAPACITY {
-        buffer.push(UnsafeCell::new(MaybeUninit::uninit()));
+    #[inline]
+    fn slot(&self, pos: u16) -> &UnsafeCell<MaybeUninit<task::Notified<T>>> {
+        &self.cells[pos as usize & self.mask]
     }
+}
 
+/// Create a new local run-queue.
+pub(super) fn local<T: 'static>() -> (Steal<T>, Local<T>) {
     let inner = Arc::new(Inner {
-        head: AtomicU32::new(0),
-        tail: AtomicU16::new(0),
-        buffer: make_fixed_size(buffer.into_boxed_slice()),
+        head: CachePadded::new(AtomicU32::new(0)),
+        tail: CachePadded::new(AtomicU16::new(0)),
+        buf: AtomicPtr::new(Box::into_raw(Buf::new(LOCAL_QUEUE_CAPACITY, 0))),
+        reader: AtomicU64::new(0),
+        retired: UnsafeCell::new(Vec::new()),
     });
 
     let local = Local {
@@ -115,7 +220,11 @@ impl<T> Local<T> {
             // safety: this is the **only** thread that updates this cell.
             let tail = unsafe { self.inner.tail.unsync_load() };
 
-            if tail.wrapping_sub(steal) < LOCAL_QUEUE_CAPACITY as u16 {
+            // safety: this is the **only** thread that replaces this pointer.
+            let buf = self.inner.buf.load(Relaxed);
+            let cap = unsafe { (*buf).mask.wrapping_add(1) };
+
+            if tail.wrapping_sub(steal) < cap as u16 {
                 // There is capacity for the task
                 break tail;
             } else if steal != real {
@@ -124,9 +233,18 @@ impl<T> Local<T> {
                 inject.push(task);
                 stats.incr_overflow_count(1);
                 return;
+            } else if cap < MAX_LOCAL_QUEUE_CAPACITY {
+                // Nobody is mid-steal and there's room left to grow into, so
+                // double the buffer instead of spilling into the inject
+                // queue. `grow` re-validates that against `head` itself
+                // before copying anything, so a stealer sneaking in between
+                // our read above and the call just means we loop and
+                // re-read fresh state.
+                self.grow(buf, real, tail);
+                continue;
             } else {
-                // Push the current task and half of the queue into the
-                // inject queue.
+                // Already at the cap on buffer growth. Push the current task
+                // and half of the queue into the inject queue.
                 match self.push_overflow(task, real, tail, inject, stats) {
                     Ok(_) => return,
                     // Lost the race, try again
@@ -137,10 +255,10 @@ impl<T> Local<T> {
             }
         };
 
-        // Map the position to a slot index.
-        let idx = tail as usize & MASK;
+        // safety: this is the **only** thread that replaces this pointer.
+        let buf = self.inner.buf.load(Relaxed);
 
-        self.inner.buffer[idx].with_mut(|ptr| {
+        unsafe { &*buf }.slot(tail).with_mut(|ptr| {
             // Write the task to the slot
             //
             // Safety: There is only one producer and the above `if`
@@ -156,12 +274,88 @@ impl<T> Local<T> {
         self.inner.tail.store(tail.wrapping_add(1), Release);
     }
 
+    /// Doubles the capacity of the local queue's backing buffer.
+    ///
+    /// `push_back` only calls this after observing no stealer is mid-steal
+    /// (`head`'s steal and real components equal `real_head`), but that
+    /// observation is stale by the time we get here — a stealer's CAS in
+    /// `claim_batch` could land in between, and it would read out of the
+    /// same `old` buffer we're about to copy out of via a bare `ptr::read`,
+    /// racing us for the same slots. So before copying anything, claim the
+    /// whole live range ourselves, the same way an in-progress steal would:
+    /// set `steal` to `tail` while leaving `real` at `real_head`. That CAS
+    /// makes `claim_batch`'s own `steal != real` guard reject any
+    /// concurrent steal attempt until we close the window again below.
+    fn grow(&mut self, old_ptr: *mut Buf<T>, real_head: u16, tail: u16) {
+        if self
+            .inner
+            .head
+            .compare_exchange(
+                pack(real_head, real_head),
+                pack(tail, real_head),
+                AcqRel,
+                Relaxed,
+            )
+            .is_err()
+        {
+            // Lost the race: `head` moved since `push_back` last read it,
+            // most likely a steal starting or finishing. Let the caller
+            // loop back around and re-read fresh state.
+            return;
+        }
+
+        let old = unsafe { &*old_ptr };
+        let new_cap = (old.mask + 1) * 2;
+        let mut new_buf = Buf::new(new_cap, old.generation.wrapping_add(1));
+
+        let len = tail.wrapping_sub(real_head);
+
+        for i in 0..len {
+            let pos = real_head.wrapping_add(i);
+
+            // safety: the CAS above claimed `real_head..tail` exclusively,
+            // and the corresponding slots in `new_buf` have not been
+            // published yet.
+            let task = old
+                .slot(pos)
+                .with(|ptr| unsafe { ptr::read((*ptr).as_ptr()) });
+            new_buf
+                .slot(pos)
+                .with_mut(|ptr| unsafe { ptr::write((*ptr).as_mut_ptr(), task) });
+        }
+
+        let new_ptr = Box::into_raw(new_buf);
+
+        // Publish the new buffer. Synchronizes with the `Acquire` load a
+        // stealer performs while pinning itself to a generation in
+        // `Inner::pin_reader`.
+        self.inner.buf.store(new_ptr, Release);
+
+        // safety: only the producer thread touches `retired`.
+        self.inner
+            .retired
+            .with_mut(|retired| unsafe { (*retired).push(Box::from_raw(old_ptr)) });
+
+        self.inner.reclaim_retired();
+
+        // Close the window we opened above, same as `close_steal_window`
+        // does for a real steal. Nothing else can have touched `head`
+        // while the window was open (the CAS above blocks `claim_batch`,
+        // and only the single producer thread ever calls `grow` or
+        // `pop`), so a plain store is enough.
+        self.inner.head.store(pack(real_head, real_head), Release);
+    }
+
     /// Moves a batch of tasks into the inject queue.
     ///
     /// This will temporarily make some of the tasks unavailable to stealers.
     /// Once `push_overflow` is done, a notification is sent out, so if other
     /// workers "missed" some of the tasks during a steal, they will get
     /// another opportunity.
+    ///
+    /// Only reached once the buffer has already grown to
+    /// `MAX_LOCAL_QUEUE_CAPACITY`; below that cap, `push_back` grows the
+    /// buffer instead of overflowing.
     #[inline(never)]
     fn push_overflow(
         &mut self,
@@ -171,15 +365,19 @@ impl<T> Local<T> {
         inject: &Inject<T>,
         stats: &mut WorkerStatsBatcher,
     ) -> Result<(), task::Notified<T>> {
-        /// How many elements are we taking from the local queue.
-        ///
-        /// This is one less than the number of tasks pushed to the inject
-        /// queue as we are also inserting the `task` argument.
-        const NUM_TASKS_TAKEN: u16 = (LOCAL_QUEUE_CAPACITY / 2) as u16;
+        // safety: this is the **only** thread that replaces this pointer.
+        let buf_ptr = self.inner.buf.load(Relaxed);
+        let cap = unsafe { (*buf_ptr).mask.wrapping_add(1) };
+
+        // How many elements are we taking from the local queue.
+        //
+        // This is one less than the number of tasks pushed to the inject
+        // queue as we are also inserting the `task` argument.
+        let num_tasks_taken = (cap / 2) as u16;
 
         assert_eq!(
             tail.wrapping_sub(head) as usize,
-            LOCAL_QUEUE_CAPACITY,
+            cap,
             "queue is not full; tail = {}; head = {}",
             tail,
             head
@@ -203,8 +401,8 @@ impl<T> Local<T> {
             .compare_exchange(
                 prev,
                 pack(
-                    head.wrapping_add(NUM_TASKS_TAKEN),
-                    head.wrapping_add(NUM_TASKS_TAKEN),
+                    head.wrapping_add(num_tasks_taken),
+                    head.wrapping_add(num_tasks_taken),
                 ),
                 Release,
                 Relaxed,
@@ -219,24 +417,27 @@ impl<T> Local<T> {
 
         /// An iterator that takes elements out of the run queue.
         struct BatchTaskIter<'a, T: 'static> {
-            buffer: &'a [UnsafeCell<MaybeUninit<task::Notified<T>>>; LOCAL_QUEUE_CAPACITY],
-            head: u32,
-            i: u32,
+            buf: &'a Buf<T>,
+            head: u16,
+            i: u16,
+            num_tasks_taken: u16,
         }
         impl<'a, T: 'static> Iterator for BatchTaskIter<'a, T> {
             type Item = task::Notified<T>;
 
             #[inline]
             fn next(&mut self) -> Option<task::Notified<T>> {
-                if self.i == u32::from(NUM_TASKS_TAKEN) {
+                if self.i == self.num_tasks_taken {
                     None
                 } else {
-                    let i_idx = self.i.wrapping_add(self.head) as usize & MASK;
-                    let slot = &self.buffer[i_idx];
+                    let pos = self.head.wrapping_add(self.i);
 
                     // safety: Our CAS from before has assumed exclusive ownership
                     // of the task pointers in this range.
-                    let task = slot.with(|ptr| unsafe { ptr::read((*ptr).as_ptr()) });
+                    let task = self
+                        .buf
+                        .slot(pos)
+                        .with(|ptr| unsafe { ptr::read((*ptr).as_ptr()) });
 
                     self.i += 1;
                     Some(task)
@@ -247,23 +448,36 @@ impl<T> Local<T> {
         // safety: The CAS above ensures that no consumer will look at these
         // values again, and we are the only producer.
         let batch_iter = BatchTaskIter {
-            buffer: &*self.inner.buffer,
-            head: head as u32,
+            buf: unsafe { &*buf_ptr },
+            head,
             i: 0,
+            num_tasks_taken,
         };
         inject.push_batch(batch_iter.chain(std::iter::once(task)));
 
         // Add 1 to factor in the task currently being scheduled.
-        stats.incr_overflow_count(NUM_TASKS_TAKEN + 1);
+        stats.incr_overflow_count(num_tasks_taken + 1);
 
         Ok(())
     }
 
-    /// Pops a task from the local queue.
+    /// Pops a task from the local queue, the same way a stealer would take
+    /// a single task: by CAS-ing `real` forward.
+    ///
+    /// A tail-side fast path (tentatively decrementing `tail`, then
+    /// consulting `head` to detect and resolve a race with a concurrent
+    /// stealer, as in a Chase-Lev deque's owner-side `pop`) was tried here
+    /// and removed: decrementing `tail` before confirming the queue is
+    /// non-empty can transiently publish `tail < real`, and every other
+    /// reader in this file (`claim_batch`, `grow`) computes availability as
+    /// an *unsigned* `tail.wrapping_sub(real)`. A concurrent stealer
+    /// observing that transient state would wrap the subtraction to
+    /// `0xffff` and believe tens of thousands of tasks were available,
+    /// then read uninitialized slots.
     pub(super) fn pop(&mut self) -> Option<task::Notified<T>> {
         let mut head = self.inner.head.load(Acquire);
 
-        let idx = loop {
+        let pos = loop {
             let (steal, real) = unpack(head);
 
             // safety: this is the **only** thread that updates this cell.
@@ -292,12 +506,19 @@ impl<T> Local<T> {
                 .compare_exchange(head, next, AcqRel, Acquire);
 
             match res {
-                Ok(_) => break real as usize & MASK,
+                Ok(_) => break real,
                 Err(actual) => head = actual,
             }
         };
 
-        Some(self.inner.buffer[idx].with(|ptr| unsafe { ptr::read(ptr).assume_init() }))
+        // safety: this is the **only** thread that replaces this pointer.
+        let buf = self.inner.buf.load(Relaxed);
+
+        Some(
+            unsafe { &*buf }
+                .slot(pos)
+                .with(|ptr| unsafe { ptr::read(ptr).assume_init() }),
+        )
     }
 }
 
@@ -312,6 +533,28 @@ impl<T> Steal<T> {
         dst: &mut Local<T>,
         dst_stats: &mut WorkerStatsBatcher,
         src_stats: &WorkerStats,
+    ) -> Option<task::Notified<T>> {
+        self.steal_into_with(dst, dst_stats, src_stats, |n| n - n / 2)
+    }
+
+    /// Steals tasks from `self` and places them into `dst`, letting the
+    /// caller decide how many to take.
+    ///
+    /// `count` is called once, with a snapshot of the number of tasks
+    /// currently available to steal, and returns how many of them to take.
+    /// The result is clamped to both that available count and half of
+    /// `dst`'s capacity, so callers can implement adaptive
+    /// policies (steal fewer when many workers are busy, or steal a single
+    /// task for latency-sensitive modes) without duplicating the CAS loop
+    /// in `steal_into2`. Like `steal_into_inject`'s `count_fn`, `count` is
+    /// evaluated exactly once against that snapshot, not re-run against a
+    /// fresher value on every `claim_batch` CAS retry.
+    pub(super) fn steal_into_with(
+        &self,
+        dst: &mut Local<T>,
+        dst_stats: &mut WorkerStatsBatcher,
+        src_stats: &WorkerStats,
+        count: impl FnOnce(usize) -> usize,
     ) -> Option<task::Notified<T>> {
         // Safety: the caller is the only thread that mutates `dst.tail` and
         // holds a mutable reference.
@@ -322,15 +565,22 @@ impl<T> Steal<T> {
         // from `dst` there may not be enough capacity to steal.
         let (steal, _) = unpack(dst.inner.head.load(Acquire));
 
-        if dst_tail.wrapping_sub(steal) > LOCAL_QUEUE_CAPACITY as u16 / 2 {
+        // safety: `dst` is owned by the calling thread, so its buffer cannot
+        // be concurrently replaced.
+        let dst_cap = unsafe { (*dst.inner.buf.load(Relaxed)).mask.wrapping_add(1) };
+
+        if dst_tail.wrapping_sub(steal) > dst_cap as u16 / 2 {
             // we *could* try to steal less here, but for simplicity, we're just
             // going to abort.
             return None;
         }
 
+        let available = self.snapshot_available()?;
+        let wanted = count(available).min(available);
+
         // Steal the tasks into `dst`'s buffer. This does not yet expose the
         // tasks in `dst`.
-        let mut n = self.steal_into2(dst, dst_tail);
+        let mut n = self.steal_into2(dst, dst_tail, dst_cap, wanted);
 
         if n == 0 {
             // No tasks were stolen
@@ -344,11 +594,14 @@ impl<T> Steal<T> {
         n -= 1;
 
         let ret_pos = dst_tail.wrapping_add(n);
-        let ret_idx = ret_pos as usize & MASK;
 
         // safety: the value was written as part of `steal_into2` and not
-        // exposed to stealers, so no other thread can access it.
-        let ret = dst.inner.buffer[ret_idx].with(|ptr| unsafe { ptr::read((*ptr).as_ptr()) });
+        // exposed to stealers, so no other thread can access it. `dst`'s
+        // buffer cannot have changed since, as it's owned by this thread.
+        let dst_buf = unsafe { &*dst.inner.buf.load(Relaxed) };
+        let ret = dst_buf
+            .slot(ret_pos)
+            .with(|ptr| unsafe { ptr::read((*ptr).as_ptr()) });
 
         if n == 0 {
             // The `dst` queue is empty, but a single task was stolen
@@ -361,9 +614,166 @@ impl<T> Steal<T> {
         Some(ret)
     }
 
+    /// Steals a batch of tasks from `self`'s buffer directly into `inject`,
+    /// for when there's no idle worker with spare `Local` capacity to pull
+    /// work into.
+    ///
+    /// `count_fn` is handed the number of tasks currently available to
+    /// steal and returns how many to take; the result is clamped to that
+    /// availability. Returns the number of tasks moved into `inject`.
+    pub(super) fn steal_into_inject(
+        &self,
+        inject: &Inject<T>,
+        count_fn: impl FnOnce(usize) -> usize,
+    ) -> usize {
+        // `count_fn` is evaluated once, against the first snapshot of what's
+        // available; `claim_batch`'s CAS loop then only ever shrinks that
+        // request to whatever is still available on retry.
+        let available = match self.snapshot_available() {
+            Some(available) => available,
+            None => return 0,
+        };
+
+        let wanted = count_fn(available).min(available);
+
+        let (first, n, claimed_packed) = match self.claim_batch(wanted, None) {
+            Some(claim) => claim,
+            None => return 0,
+        };
+
+        // Same generation-pinning dance as `steal_into2`: the producer may
+        // be growing (and retiring) its buffer concurrently with the CAS
+        // in `claim_batch`.
+        let src_buf = self.0.pin_reader();
+
+        /// An iterator that takes elements out of the run queue.
+        struct BatchTaskIter<'a, T: 'static> {
+            buf: &'a Buf<T>,
+            head: u16,
+            i: u16,
+            n: u16,
+        }
+        impl<'a, T: 'static> Iterator for BatchTaskIter<'a, T> {
+            type Item = task::Notified<T>;
+
+            #[inline]
+            fn next(&mut self) -> Option<task::Notified<T>> {
+                if self.i == self.n {
+                    None
+                } else {
+                    let pos = self.head.wrapping_add(self.i);
+
+                    // safety: `claim_batch`'s CAS gave us exclusive ownership
+                    // of this range.
+                    let task = self
+                        .buf
+                        .slot(pos)
+                        .with(|ptr| unsafe { ptr::read((*ptr).as_ptr()) });
+
+                    self.i += 1;
+                    Some(task)
+                }
+            }
+        }
+
+        inject.push_batch(BatchTaskIter {
+            buf: src_buf,
+            head: first,
+            i: 0,
+            n,
+        });
+
+        self.0.unpin_reader();
+        self.close_steal_window(claimed_packed);
+
+        n as usize
+    }
+
     // Steal tasks from `self`, placing them into `dst`. Returns the number of
     // tasks that were stolen.
-    fn steal_into2(&self, dst: &mut Local<T>, dst_tail: u16) -> u16 {
+    fn steal_into2(&self, dst: &mut Local<T>, dst_tail: u16, dst_cap: usize, wanted: usize) -> u16 {
+        let (first, n, claimed_packed) = match self.claim_batch(wanted, Some((dst_cap / 2) as u16))
+        {
+            Some(claim) => claim,
+            None => return 0,
+        };
+
+        // We now exclusively own the range `first..first+n` in the source
+        // queue, acquired via the CAS in `claim_batch`. But the producer on
+        // the other end may be concurrently growing (and retiring) its
+        // buffer, so we must not dereference a buffer pointer read before we
+        // held that range; pin ourselves to whatever generation is current
+        // now.
+        let src_buf = self.0.pin_reader();
+
+        // safety: `dst` queue is empty in the stolen range and we are the
+        // only producer to this queue.
+        let dst_buf = unsafe { &*dst.inner.buf.load(Relaxed) };
+
+        for i in 0..n {
+            // Compute the positions
+            let src_pos = first.wrapping_add(i);
+            let dst_pos = dst_tail.wrapping_add(i);
+
+            // Read the task
+            //
+            // safety: We acquired the task with the atomic exchange above.
+            let task = src_buf
+                .slot(src_pos)
+                .with(|ptr| unsafe { ptr::read((*ptr).as_ptr()) });
+
+            // Write the task to the new slot
+            //
+            // safety: `dst` queue is empty and we are the only producer to
+            // this queue.
+            dst_buf
+                .slot(dst_pos)
+                .with_mut(|ptr| unsafe { ptr::write((*ptr).as_mut_ptr(), task) });
+        }
+
+        self.0.unpin_reader();
+        self.close_steal_window(claimed_packed);
+
+        n
+    }
+
+    /// Reads a snapshot of how many tasks are currently available to steal,
+    /// or `None` if another steal is already in flight or the queue looks
+    /// empty.
+    ///
+    /// `steal_into_with` and `steal_into_inject` both use this to evaluate
+    /// their caller-supplied `count`/`count_fn` exactly once, against a
+    /// single consistent snapshot, rather than letting `claim_batch`'s CAS
+    /// retry loop re-run it against a moving target.
+    fn snapshot_available(&self) -> Option<usize> {
+        let (steal, real) = unpack(self.0.head.load(Acquire));
+
+        if steal != real {
+            return None;
+        }
+
+        let available = self.0.tail.load(Acquire).wrapping_sub(real) as usize;
+
+        if available == 0 {
+            None
+        } else {
+            Some(available)
+        }
+    }
+
+    /// Claims `wanted` tasks (clamped to what's actually still available,
+    /// and to `max` if given) from the head of `self`'s queue without
+    /// copying them out of the buffer yet, leaving the steal window open
+    /// (`steal != real`) until the caller finishes and calls
+    /// `close_steal_window`.
+    ///
+    /// `wanted` is normally the result of evaluating a caller's `count`
+    /// closure once against a `snapshot_available` reading; unlike the
+    /// `available` reread on every iteration below, it does not change
+    /// across CAS retries. Returns the claimed range's start position, its
+    /// length, and the packed head value right after the claiming CAS (to
+    /// hand to `close_steal_window`).
+    fn claim_batch(&self, wanted: usize, max: Option<u16>) -> Option<(u16, u16, u32)> {
         let mut prev_packed = self.0.head.load(Acquire);
         let mut next_packed;
 
@@ -374,16 +784,28 @@ impl<T> Steal<T> {
             // If these two do not match, another thread is concurrently
             // stealing from the queue.
             if src_head_steal != src_head_real {
-                return 0;
+                return None;
             }
 
             // Number of available tasks to steal
-            let n = src_tail.wrapping_sub(src_head_real);
-            let n = n - n / 2;
+            let available = src_tail.wrapping_sub(src_head_real) as usize;
 
-            if n == 0 {
+            if available == 0 {
                 // No tasks available to steal
-                return 0;
+                return None;
+            }
+
+            // Clamp the caller's request to both what's actually available
+            // now and the caller-supplied ceiling.
+            let mut n = wanted.min(available);
+            if let Some(max) = max {
+                n = n.min(max as usize);
+            }
+            let n = n as u16;
+
+            if n == 0 {
+                // The caller chose not to steal anything
+                return None;
             }
 
             // Update the real head index to acquire the tasks.
@@ -405,40 +827,19 @@ impl<T> Steal<T> {
             }
         };
 
-        assert!(n <= LOCAL_QUEUE_CAPACITY as u16 / 2, "actual = {}", n);
-
         let (first, _) = unpack(next_packed);
 
-        // Take all the tasks
-        for i in 0..n {
-            // Compute the positions
-            let src_pos = first.wrapping_add(i);
-            let dst_pos = dst_tail.wrapping_add(i);
-
-            // Map to slots
-            let src_idx = src_pos as usize & MASK;
-            let dst_idx = dst_pos as usize & MASK;
-
-            // Read the task
-            //
-            // safety: We acquired the task with the atomic exchange above.
-            let task = self.0.buffer[src_idx].with(|ptr| unsafe { ptr::read((*ptr).as_ptr()) });
-
-            // Write the task to the new slot
-            //
-            // safety: `dst` queue is empty and we are the only producer to
-            // this queue.
-            dst.inner.buffer[dst_idx]
-                .with_mut(|ptr| unsafe { ptr::write((*ptr).as_mut_ptr(), task) });
-        }
-
-        let mut prev_packed = next_packed;
+        Some((first, n, next_packed))
+    }
 
-        // Update `src_head_steal` to match `src_head_real` signalling that the
-        // stealing routine is complete.
+    /// Closes the steal window opened by `claim_batch`, signalling that the
+    /// stealing routine is complete by updating `steal` to match `real`.
+    ///
+    /// `claimed_packed` is the packed head value returned by `claim_batch`.
+    fn close_steal_window(&self, mut prev_packed: u32) {
         loop {
             let head = unpack(prev_packed).1;
-            next_packed = pack(head, head);
+            let next_packed = pack(head, head);
 
             let res = self
                 .0
@@ -446,7 +847,7 @@ impl<T> Steal<T> {
                 .compare_exchange(prev_packed, next_packed, AcqRel, Acquire);
 
             match res {
-                Ok(_) => return n,
+                Ok(_) => return,
                 Err(actual) => {
                     let (actual_steal, actual_real) = unpack(actual);
 
@@ -480,6 +881,68 @@ impl<T> Inner<T> {
 
         head == tail
     }
+
+    /// Pins the calling stealer to whichever `Buf` generation is current,
+    /// publishing that generation plus one (`0` means unpinned) into `reader`
+    /// so `reclaim_retired` won't
+    /// free it out from under the read that's about to happen. Returns the
+    /// pinned buffer.
+    ///
+    /// A single slot suffices: `claim_batch` rejects a steal attempt
+    /// whenever `head`'s `steal` and `real` halves already differ, so only
+    /// one steal window — and thus only one call to `pin_reader` — can ever
+    /// be outstanding on a given `Inner` at a time.
+    fn pin_reader(&self) -> &Buf<T> {
+        loop {
+            let ptr = self.buf.load(Acquire);
+            let generation = unsafe { (*ptr).generation };
+
+            self.reader.store(generation.wrapping_add(1), Release);
+
+            // The producer could have grown and retired `ptr` in the window
+            // between our load above and the store that just published our
+            // interest in `generation`. If so, our claim is still a safe
+            // (if conservative) lower bound on what we might read, but
+            // `ptr` itself may already be gone — re-read and retry against
+            // the latest buffer instead.
+            if self.buf.load(Acquire) == ptr {
+                return unsafe { &*ptr };
+            }
+
+            self.reader.store(0, Release);
+        }
+    }
+
+    fn unpin_reader(&self) {
+        self.reader.store(0, Release);
+    }
+
+    /// Frees any retired buffers that no pinned stealer can still be reading
+    /// from.
+    ///
+    /// safety: only the producer thread (the owner of the corresponding
+    /// `Local`) may call this.
+    fn reclaim_retired(&self) {
+        let active_generation = self.reader.load(Acquire);
+
+        self.retired.with_mut(|retired| {
+            let retired = unsafe { &mut *retired };
+
+            if active_generation == 0 {
+                retired.clear();
+            } else {
+                retired.retain(|buf| buf.generation >= active_generation - 1)
+            }
+        });
+    }
+}
+
+impl<T> Drop for Inner<T> {
+    fn drop(&mut self) {
+        // safety: `Inner` is being dropped, so there are no outstanding
+        // `Local` or `Steal` handles left that could be reading `buf`.
+        drop(unsafe { Box::from_raw(*self.buf.get_mut()) });
+    }
 }
 
 /// Split the head value into the real head and the index a stealer is working
@@ -500,3 +963,125 @@ fn pack(steal: u16, real: u16) -> u32 {
 fn test_local_queue_capacity() {
     assert!(LOCAL_QUEUE_CAPACITY - 1 <= u8::MAX as usize);
 }
+
+#[test]
+fn test_max_local_queue_capacity_fits_u16_index_space() {
+    assert!(MAX_LOCAL_QUEUE_CAPACITY.is_power_of_two());
+    assert!(MAX_LOCAL_QUEUE_CAPACITY - 1 <= u16::MAX as usize);
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+    use crate::runtime::task::Schedule;
+    use loom::thread;
+
+    struct LoomSchedule;
+
+    impl Schedule for LoomSchedule {
+        fn release(&self, _task: &task::Task<Self>) -> Option<task::Task<Self>> {
+            None
+        }
+
+        fn schedule(&self, _task: task::Notified<Self>) {}
+    }
+
+    fn notified() -> task::Notified<LoomSchedule> {
+        let (handle, notified) = task::joinable(async {}, &LoomSchedule);
+        drop(handle);
+        notified
+    }
+
+    fn drain(local: &mut Local<LoomSchedule>) {
+        while local.pop().is_some() {}
+    }
+
+    /// Like `drain`, but returns how many tasks were left in the queue.
+    fn drain_count(local: &mut Local<LoomSchedule>) -> usize {
+        let mut count = 0;
+        while local.pop().is_some() {
+            count += 1;
+        }
+        count
+    }
+
+    /// Regression test for the race `Local::grow` used to have: it read
+    /// tasks out of the old buffer via a bare `ptr::read`, relying only on
+    /// the stale `steal == real` check `push_back` made before calling it.
+    /// A concurrent `steal_into` could claim (and read) the same range out
+    /// from under it. `grow` now re-claims the range itself via CAS before
+    /// copying, so this should never panic or double-read a task.
+    #[test]
+    fn push_back_grow_vs_concurrent_steal() {
+        loom::model(|| {
+            let (steal, mut local) = local::<LoomSchedule>();
+            let mut stats = WorkerStatsBatcher::default();
+            let inject = Inject::new();
+
+            // Fill to exactly the initial capacity so the following push has
+            // to grow the buffer (or, if a steal window is open, overflow to
+            // `inject`) while a stealer may be mid-steal.
+            for _ in 0..LOCAL_QUEUE_CAPACITY {
+                local.push_back(notified(), &inject, &mut stats);
+            }
+
+            let stealer = thread::spawn(move || {
+                let (_dst_steal, mut dst_local) = local::<LoomSchedule>();
+                let mut dst_stats = WorkerStatsBatcher::default();
+                let src_stats = WorkerStats::default();
+
+                steal.steal_into(&mut dst_local, &mut dst_stats, &src_stats);
+                drain(&mut dst_local);
+            });
+
+            local.push_back(notified(), &inject, &mut stats);
+
+            stealer.join().unwrap();
+
+            drain(&mut local);
+            while inject.pop().is_some() {}
+        });
+    }
+
+    /// `Local::pop` claims its task via the same CAS `claim_batch` uses, so
+    /// a pop racing a steal should always resolve to exactly one side
+    /// winning each task, never both.
+    ///
+    /// Counting just `popped.is_some()` and `stolen.is_some()` wouldn't
+    /// catch a double-claim or a lost task: both are already bounded to
+    /// `{0, 1}`, so their sum is vacuously `<= 2` no matter what happened to
+    /// the other task `steal_into` pulled into `dst_local`, or to the
+    /// source queue's own leftovers. Draining every queue afterward and
+    /// counting every task actually recovered is what makes a double-claim
+    /// (total > 2) or a lost task (total < 2) fail the model.
+    #[test]
+    fn pop_vs_concurrent_steal() {
+        loom::model(|| {
+            let (steal, mut local) = local::<LoomSchedule>();
+            let mut stats = WorkerStatsBatcher::default();
+            let inject = Inject::new();
+
+            local.push_back(notified(), &inject, &mut stats);
+            local.push_back(notified(), &inject, &mut stats);
+
+            let stealer = thread::spawn(move || {
+                let (_dst_steal, mut dst_local) = local::<LoomSchedule>();
+                let mut dst_stats = WorkerStatsBatcher::default();
+                let src_stats = WorkerStats::default();
+
+                let stolen = steal.steal_into(&mut dst_local, &mut dst_stats, &src_stats);
+                stolen.is_some() as usize + drain_count(&mut dst_local)
+            });
+
+            let popped = local.pop().is_some() as usize;
+
+            let recovered_by_stealer = stealer.join().unwrap();
+
+            let total = popped + recovered_by_stealer + drain_count(&mut local);
+
+            // Exactly two tasks went in; exactly two must come back out
+            // across the pop, the steal, and both queues' leftovers.
+            assert_eq!(total, 2);
+        });
+    }
+}